@@ -1,4 +1,4 @@
-use cargo_metadata::Message;
+use cargo_metadata::{Message, Metadata, Package};
 use clap::{Parser, Subcommand, ValueEnum};
 use std::env;
 use std::fs;
@@ -22,7 +22,56 @@ struct NanosMetadata {
     flags: String,
     icon: String,
     icon_small: String,
+    // Large color icon used by the touchscreen devices (Stax, Flex). Falls
+    // back to `icon` when a device-specific large icon isn't provided.
+    icon_large: Option<String>,
     name: Option<String>,
+    // `core`/`alloc` (and friends) to pass to `-Z build-std`, for apps that
+    // need `alloc`/`embedded-alloc` and can't rely on `.cargo/config.toml`.
+    build_std: Option<Vec<String>>,
+    build_std_features: Option<Vec<String>>,
+}
+
+// A `package.metadata.ledger.<device>` table: every field is optional, and
+// any field present here wins over the shared `NanosMetadata` section for
+// that device, so e.g. Nano X's BLE-only curve set doesn't have to apply
+// to Nano S too.
+#[derive(Debug, Deserialize, Default)]
+struct NanosMetadataOverride {
+    curve: Option<Vec<String>>,
+    path: Option<Vec<String>>,
+    flags: Option<String>,
+    icon: Option<String>,
+    icon_small: Option<String>,
+    icon_large: Option<String>,
+    name: Option<String>,
+}
+
+impl NanosMetadata {
+    fn with_override(mut self, over: NanosMetadataOverride) -> Self {
+        if let Some(curve) = over.curve {
+            self.curve = curve;
+        }
+        if let Some(path) = over.path {
+            self.path = path;
+        }
+        if let Some(flags) = over.flags {
+            self.flags = flags;
+        }
+        if let Some(icon) = over.icon {
+            self.icon = icon;
+        }
+        if let Some(icon_small) = over.icon_small {
+            self.icon_small = icon_small;
+        }
+        if over.icon_large.is_some() {
+            self.icon_large = over.icon_large;
+        }
+        if over.name.is_some() {
+            self.name = over.name;
+        }
+        self
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -49,6 +98,19 @@ struct CliArgs {
     ))]
     hex_next_to_json: bool,
 
+    #[clap(long)]
+    #[clap(value_name = "NAME")]
+    #[clap(help = "Name of the package to build, when run from a Cargo workspace")]
+    package: Option<String>,
+
+    #[clap(long)]
+    #[clap(help = concat!(
+        "Write app.json (and, with --hex-next-to-json, app.hex) next to Cargo.toml instead of under cargo's target directory.",
+        " ",
+        "Restores the pre-workspace-aware placement for users who depend on it.",
+    ))]
+    legacy_manifest_dir: bool,
+
     #[clap(subcommand)]
     command: MainCommand,
 }
@@ -58,6 +120,8 @@ enum Device {
     Nanos,
     Nanox,
     Nanosplus,
+    Stax,
+    Flex,
 }
 
 impl AsRef<str> for Device {
@@ -66,6 +130,33 @@ impl AsRef<str> for Device {
             Device::Nanos => "nanos",
             Device::Nanox => "nanox",
             Device::Nanosplus => "nanosplus",
+            Device::Stax => "stax",
+            Device::Flex => "flex",
+        }
+    }
+}
+
+impl Device {
+    // Name of the env var holding this device's SDK/target path, checked
+    // before falling back to the shared `LEDGER_TARGETS` directory.
+    fn sdk_env_var(&self) -> Option<&'static str> {
+        match self {
+            Device::Stax => Some("STAX_SDK"),
+            Device::Flex => Some("FLEX_SDK"),
+            _ => None,
+        }
+    }
+
+    // speculos' `--model` names don't always match ours (e.g. `nanosplus`
+    // is `nanosp` to speculos), so this can't be `as_ref()`, which is also
+    // used for the unrelated target-file/JSON naming convention.
+    fn speculos_model(&self) -> &'static str {
+        match self {
+            Device::Nanos => "nanos",
+            Device::Nanox => "nanox",
+            Device::Nanosplus => "nanosp",
+            Device::Stax => "stax",
+            Device::Flex => "flex",
         }
     }
 }
@@ -85,48 +176,293 @@ enum MainCommand {
         #[clap(last = true)]
         remaining_args: Vec<String>,
     },
+    #[clap(about = "build the project and run it under the speculos emulator")]
+    Run {
+        #[clap(value_enum)]
+        #[clap(help = "device to emulate")]
+        device: Device,
+        #[clap(last = true)]
+        #[clap(help = "extra arguments forwarded to speculos (seed, APDU port, display mode, ...)")]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "alias for `run`, for use from test harnesses")]
+    Test {
+        #[clap(value_enum)]
+        #[clap(help = "device to emulate")]
+        device: Device,
+        #[clap(last = true)]
+        #[clap(help = "extra arguments forwarded to speculos (seed, APDU port, display mode, ...)")]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "assemble a versioned release bundle for one or more devices")]
+    Dist {
+        #[clap(value_enum)]
+        #[clap(help = "devices to bundle (defaults to all supported devices)")]
+        devices: Vec<Device>,
+    },
+}
+
+// What to do with the build artifacts once `build_app` has produced them.
+enum PostBuild {
+    None,
+    Install,
+    Speculos(Vec<String>),
+}
+
+// Where `build_app` placed its outputs, returned so callers like `dist`
+// can collect the manifest and the files it references.
+struct BuildArtifacts {
+    out_dir: PathBuf,
+    app_json: PathBuf,
 }
 
 fn main() {
     let Cli::Ledger(cli) = Cli::parse();
 
+    if let MainCommand::Setup = cli.command {
+        install_targets();
+        return;
+    }
+
+    // Fetch crate metadata (without dependencies) once and resolve the app
+    // crate up front, so every subcommand and every device in a `dist` run
+    // shares the same `cargo metadata` call and the same resolved package.
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    let res = cmd.no_deps().exec().unwrap();
+    let this_pkg = resolve_package(&res, &cli.package);
+
     match cli.command {
-        MainCommand::Setup => install_targets(),
+        MainCommand::Setup => unreachable!("handled above"),
         MainCommand::Build {
             device: d,
             load: a,
             remaining_args: r,
         } => {
-            build_app(d, a, cli.use_prebuilt, cli.hex_next_to_json, r);
+            let post_build = if a {
+                PostBuild::Install
+            } else {
+                PostBuild::None
+            };
+            build_app(
+                d,
+                post_build,
+                cli.use_prebuilt,
+                cli.hex_next_to_json,
+                &res,
+                this_pkg,
+                cli.legacy_manifest_dir,
+                r,
+            );
+        }
+        MainCommand::Run {
+            device: d,
+            remaining_args: r,
+        }
+        | MainCommand::Test {
+            device: d,
+            remaining_args: r,
+        } => {
+            build_app(
+                d,
+                PostBuild::Speculos(r),
+                cli.use_prebuilt,
+                cli.hex_next_to_json,
+                &res,
+                this_pkg,
+                cli.legacy_manifest_dir,
+                Vec::new(),
+            );
+        }
+        MainCommand::Dist { devices } => {
+            let devices = if devices.is_empty() {
+                vec![
+                    Device::Nanos,
+                    Device::Nanox,
+                    Device::Nanosplus,
+                    Device::Stax,
+                    Device::Flex,
+                ]
+            } else {
+                devices
+            };
+            dist(
+                devices,
+                cli.use_prebuilt,
+                cli.hex_next_to_json,
+                &res,
+                this_pkg,
+                cli.legacy_manifest_dir,
+            );
         }
     }
 }
 
+// Resolve the app crate: an explicit `--package` wins, otherwise fall back
+// to the resolve-root package when one is known. We run `cargo metadata`
+// with `--no-deps`, so `resolve` is always `None` in practice; the common
+// single-package case still has an unambiguous answer, so use that instead
+// of making every ordinary, non-workspace crate require `--package`. Only a
+// genuine multi-member workspace with no resolve graph and no `--package`
+// has no sensible default, so that case is a hard error rather than a guess.
+fn resolve_package<'a>(res: &'a Metadata, package: &Option<String>) -> &'a Package {
+    if let Some(name) = package {
+        return res
+            .packages
+            .iter()
+            .find(|p| &p.name == name)
+            .unwrap_or_else(|| panic!("no package named '{}' in this workspace", name));
+    }
+
+    if let Some(id) = res.resolve.as_ref().and_then(|r| r.root.as_ref()) {
+        return res
+            .packages
+            .iter()
+            .find(|p| &p.id == id)
+            .expect("resolve root package is missing from cargo metadata output");
+    }
+
+    match res.packages.as_slice() {
+        [only] => only,
+        _ => panic!(
+            "multiple packages found and no resolve graph to pick a default from; pass --package <NAME>"
+        ),
+    }
+}
+
+// Build the app for each of `devices` and collect the hex, manifest and
+// referenced icon(s) into `target/dist/<app>-<version>/<device>/`, ready
+// to publish.
+fn dist(
+    devices: Vec<Device>,
+    use_prebuilt: Option<PathBuf>,
+    hex_next_to_json: bool,
+    res: &Metadata,
+    this_pkg: &Package,
+    legacy_manifest_dir: bool,
+) {
+    assert!(
+        !legacy_manifest_dir,
+        "--legacy-manifest-dir isn't supported with dist: dist always assembles its bundle under the target directory"
+    );
+
+    let crate_dir = this_pkg.manifest_path.parent().unwrap();
+    let dist_dir = res
+        .target_directory
+        .clone()
+        .join("dist")
+        .join(format!("{}-{}", this_pkg.name, this_pkg.version));
+
+    for device in devices {
+        let device_str = device.as_ref().to_string();
+        let artifacts = build_app(
+            device,
+            PostBuild::None,
+            use_prebuilt.clone(),
+            hex_next_to_json,
+            res,
+            this_pkg,
+            legacy_manifest_dir,
+            Vec::new(),
+        );
+
+        let bundle_dir = dist_dir.join(&device_str);
+        fs::create_dir_all(&bundle_dir).expect("couldn't create dist bundle directory");
+
+        let mut manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&artifacts.app_json).unwrap()).unwrap();
+
+        // `binary` may point outside `out_dir` (e.g. next to the exe), so
+        // copy the hex into the bundle itself and point the manifest at it.
+        let hex_name = manifest["binary"].as_str().unwrap().to_string();
+        fs::copy(artifacts.out_dir.join(&hex_name), bundle_dir.join("app.hex")).unwrap();
+        manifest["binary"] = "app.hex".into();
+
+        // The icon path in the manifest is relative to the crate root;
+        // copy it alongside and point the bundled JSON at the copy.
+        if let Some(icon) = manifest["icon"].as_str().map(str::to_string) {
+            let icon_path = Path::new(&icon);
+            let icon_src = if icon_path.is_absolute() {
+                icon_path.to_path_buf()
+            } else {
+                crate_dir.join(icon_path)
+            };
+            if icon_src.exists() {
+                let icon_name = icon_path.file_name().unwrap();
+                fs::copy(&icon_src, bundle_dir.join(icon_name)).unwrap();
+                manifest["icon"] = icon_name.to_string_lossy().into_owned().into();
+            }
+        }
+
+        let bundled_json =
+            fs::File::create(bundle_dir.join(artifacts.app_json.file_name().unwrap())).unwrap();
+        serde_json::to_writer_pretty(bundled_json, &manifest).unwrap();
+
+        println!("Bundled {} into {}", device_str, bundle_dir.display());
+    }
+}
+
 fn build_app(
     device: Device,
-    is_load: bool,
+    post_build: PostBuild,
     use_prebuilt: Option<PathBuf>,
     hex_next_to_json: bool,
+    res: &Metadata,
+    this_pkg: &Package,
+    legacy_manifest_dir: bool,
     remaining_args: Vec<String>,
-) {
-    let ledger_target_path = match env::var("LEDGER_TARGETS") {
-        Ok(path) => path,
-        Err(_) => String::new(),
-    };
+) -> BuildArtifacts {
+    let ledger_target_path = device
+        .sdk_env_var()
+        .and_then(|var| env::var(var).ok())
+        .or_else(|| env::var("LEDGER_TARGETS").ok())
+        .unwrap_or_default();
     let device_str = device.as_ref();
     let device_json = format!("{}.json", &device_str);
     let device_json_path = Path::new(&ledger_target_path).join(&device_json);
     println!("Using target file: {}", device_json_path.display());
 
+    let metadata_value = this_pkg
+        .metadata
+        .get("nanos")
+        .expect("package.metadata.nanos section is missing in Cargo.toml")
+        .clone();
+    let this_metadata: NanosMetadata =
+        serde_json::from_value(metadata_value).unwrap();
+
+    // Layer a per-device override, if any, from `package.metadata.ledger.<device>`.
+    let this_metadata = match this_pkg
+        .metadata
+        .get("ledger")
+        .and_then(|ledger| ledger.get(device_str))
+    {
+        Some(override_value) => {
+            let over: NanosMetadataOverride = serde_json::from_value(override_value.clone())
+                .expect("invalid package.metadata.ledger section for this device");
+            this_metadata.with_override(over)
+        }
+        None => this_metadata,
+    };
+
     let exe_path = match use_prebuilt {
         None => {
+            let mut build_args = vec![
+                "build".to_string(),
+                "--release".to_string(),
+                format!("--target={}", device_json_path.display()),
+                "--message-format=json-diagnostic-rendered-ansi".to_string(),
+            ];
+            if let Some(build_std) = &this_metadata.build_std {
+                build_args.push(format!("-Zbuild-std={}", build_std.join(",")));
+            }
+            if let Some(build_std_features) = &this_metadata.build_std_features {
+                build_args.push(format!(
+                    "-Zbuild-std-features={}",
+                    build_std_features.join(",")
+                ));
+            }
+
             let mut cargo_cmd = Command::new("cargo")
-                .args([
-                    "build",
-                    "--release",
-                    format!("--target={}", device_json_path.display()).as_str(),
-                    "--message-format=json-diagnostic-rendered-ansi",
-                ])
+                .args(&build_args)
                 .args(&remaining_args)
                 .stdout(Stdio::piped())
                 .spawn()
@@ -156,45 +492,47 @@ fn build_app(
         Some(prebuilt) => prebuilt,
     };
 
-    // Fetch crate metadata without fetching dependencies
-    let mut cmd = cargo_metadata::MetadataCommand::new();
-    let res = cmd.no_deps().exec().unwrap();
-
-    // Fetch package.metadata.nanos section
-    let this_pkg = res.packages.last().unwrap();
-    let metadata_value = this_pkg
-        .metadata
-        .get("nanos")
-        .expect("package.metadata.nanos section is missing in Cargo.toml")
-        .clone();
-    let this_metadata: NanosMetadata =
-        serde_json::from_value(metadata_value).unwrap();
-
     let current_dir = this_pkg.manifest_path.parent().unwrap();
 
+    // By default the manifest and hex live under cargo's target directory,
+    // in a per-device folder, so building doesn't dirty the source tree.
+    // `--legacy-manifest-dir` restores the old in-crate placement.
+    let out_dir = if legacy_manifest_dir {
+        current_dir.to_path_buf()
+    } else {
+        res.target_directory.clone().join("ledger").join(device_str)
+    };
+    fs::create_dir_all(&out_dir).expect("couldn't create manifest output directory");
+
+    // `--hex-next-to-json` is honored regardless of `--legacy-manifest-dir`:
+    // it always means "next to app.json" (out_dir) vs. "next to the exe".
     let hex_file_abs = if hex_next_to_json {
-        current_dir
+        out_dir.clone()
     } else {
-        exe_path.parent().unwrap()
+        exe_path.parent().unwrap().to_path_buf()
     }
     .join("app.hex");
 
     export_binary(&exe_path, &hex_file_abs);
 
-    // app.json will be placed in the app's root directory
+    // app.json is placed in out_dir (next to the hex only with `--hex-next-to-json`)
     let app_json_name = format!("app_{}.json", device.as_ref());
-    let app_json = current_dir.join(app_json_name);
+    let app_json = out_dir.join(app_json_name);
 
-    // Find hex file path relative to 'app.json'
-    let hex_file = hex_file_abs.strip_prefix(current_dir).unwrap();
+    // Find hex file path relative to 'app.json'. The hex sits right next to
+    // it only with `--hex-next-to-json`; otherwise it is next to the exe,
+    // under cargo's target directory but not under `out_dir` (neither
+    // target/ledger/<device> nor, with `--legacy-manifest-dir`, the package
+    // directory) — so this can't be a plain `strip_prefix`.
+    let hex_file = relative_path(&out_dir, &hex_file_abs);
 
     // Retrieve real data size and SDK infos from ELF
     let infos = retrieve_infos(&exe_path).unwrap();
 
-    // Modify flags to enable BLE if targetting Nano X
+    // Modify flags to enable BLE if targetting Nano X, Stax or Flex
     let flags = match device {
         Device::Nanos | Device::Nanosplus => this_metadata.flags,
-        Device::Nanox => {
+        Device::Nanox | Device::Stax | Device::Flex => {
             let base = u32::from_str_radix(this_metadata.flags.as_str(), 16)
                 .unwrap_or(0);
             format!("0x{:x}", base | 0x200)
@@ -202,10 +540,16 @@ fn build_app(
     };
 
     // Pick icon and targetid according to target
+    let icon_large = this_metadata
+        .icon_large
+        .as_ref()
+        .unwrap_or(&this_metadata.icon);
     let (targetid, icon) = match device {
         Device::Nanos => ("0x31100004", &this_metadata.icon),
         Device::Nanox => ("0x33000004", &this_metadata.icon_small),
         Device::Nanosplus => ("0x33100004", &this_metadata.icon_small),
+        Device::Stax => ("0x33200004", icon_large),
+        Device::Flex => ("0x33300004", icon_large),
     };
 
     // create manifest
@@ -232,7 +576,50 @@ fn build_app(
     }
     serde_json::to_writer_pretty(file, &json).unwrap();
 
-    if is_load {
-        install_with_ledgerctl(current_dir, &app_json);
+    match post_build {
+        PostBuild::None => (),
+        PostBuild::Install => install_with_ledgerctl(&out_dir, &app_json),
+        PostBuild::Speculos(extra_args) => run_in_speculos(&device, &exe_path, extra_args),
+    }
+
+    BuildArtifacts { out_dir, app_json }
+}
+
+// Express `target` as a path relative to `base`, using `..` components as
+// needed. Unlike `Path::strip_prefix`, this works even when neither path is
+// a prefix of the other (e.g. a non-root workspace member's directory vs.
+// the shared target directory).
+fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base_comps: Vec<_> = base.components().collect();
+    let target_comps: Vec<_> = target.components().collect();
+
+    let common = base_comps
+        .iter()
+        .zip(target_comps.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_comps.len() {
+        relative.push("..");
+    }
+    for comp in &target_comps[common..] {
+        relative.push(comp.as_os_str());
+    }
+    relative
+}
+
+// Spawn the app under the speculos emulator, forwarding any extra args
+// (seed, APDU port, display mode, ...) the user passed after `--`.
+fn run_in_speculos(device: &Device, exe_path: &Path, extra_args: Vec<String>) {
+    let status = Command::new("speculos")
+        .arg(format!("--model={}", device.speculos_model()))
+        .arg(exe_path)
+        .args(&extra_args)
+        .status()
+        .expect("couldn't launch speculos; is it installed and on PATH?");
+
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
     }
 }